@@ -0,0 +1,207 @@
+//! Build-time grammar registry.
+//!
+//! Reads `languages.toml`, clones and compiles each listed tree-sitter grammar
+//! (Helix runtime model), and generates `$OUT_DIR/grammars.rs` — the `extern "C"`
+//! parser declarations plus a `GRAMMARS` table that embeds every grammar's query
+//! files. `src/main.rs` `include!`s that file, so supporting a new language is a
+//! `languages.toml` edit rather than a source change.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+struct Grammar {
+    name: String,
+    aliases: Vec<String>,
+    repo: String,
+    rev: String,
+    /// Sub-directory of the checkout holding `src/` (some repos nest grammars).
+    path: Option<String>,
+    highlights: Option<String>,
+    injections: Option<String>,
+    locals: Option<String>,
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=languages.toml");
+
+    let manifest = fs::read_to_string("languages.toml").expect("read languages.toml");
+    let grammars = parse_grammars(&manifest);
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let grammars_dir = out_dir.join("grammars");
+    fs::create_dir_all(&grammars_dir).unwrap();
+
+    let mut externs = String::new();
+    let mut entries = String::new();
+
+    for g in &grammars {
+        let checkout = grammars_dir.join(&g.name);
+        fetch(&g.repo, &g.rev, &checkout);
+
+        let root = match &g.path {
+            Some(p) => checkout.join(p),
+            None => checkout.clone(),
+        };
+        compile(&g.name, &root.join("src"));
+
+        externs.push_str(&format!("    fn tree_sitter_{}() -> Language;\n", g.name));
+
+        let aliases = g
+            .aliases
+            .iter()
+            .map(|a| format!("{:?}", a))
+            .collect::<Vec<_>>()
+            .join(", ");
+        entries.push_str(&format!(
+            "    GrammarInfo {{\n        name: {name:?},\n        aliases: &[{aliases}],\n        language: tree_sitter_{name},\n        highlights: {highlights},\n        injections: {injections},\n        locals: {locals},\n    }},\n",
+            name = g.name,
+            aliases = aliases,
+            highlights = query_lit(&checkout, g.highlights.as_deref()),
+            injections = query_lit(&checkout, g.injections.as_deref()),
+            locals = query_lit(&checkout, g.locals.as_deref()),
+        ));
+    }
+
+    let generated = format!(
+        "unsafe extern \"C\" {{\n{externs}}}\n\npub static GRAMMARS: &[GrammarInfo] = &[\n{entries}];\n"
+    );
+    fs::write(out_dir.join("grammars.rs"), generated).unwrap();
+}
+
+/// Render a query reference as a Rust `include_str!` literal, or `""` when the
+/// grammar doesn't ship that query.
+fn query_lit(root: &Path, query: Option<&str>) -> String {
+    match query {
+        Some(rel) => {
+            let abs = root.join(rel);
+            if abs.exists() {
+                println!("cargo:rerun-if-changed={}", abs.display());
+                format!("include_str!({:?})", abs.display().to_string())
+            } else {
+                "\"\"".to_string()
+            }
+        }
+        None => "\"\"".to_string(),
+    }
+}
+
+/// Shallow-clone `repo` at `rev` into `dest` if it isn't already present.
+fn fetch(repo: &str, rev: &str, dest: &Path) {
+    if dest.join(".git").exists() {
+        return;
+    }
+    run(Command::new("git").args(["init", "-q"]).arg(dest));
+    run(Command::new("git")
+        .current_dir(dest)
+        .args(["remote", "add", "origin", repo]));
+    run(Command::new("git")
+        .current_dir(dest)
+        .args(["fetch", "-q", "--depth", "1", "origin", rev]));
+    run(Command::new("git")
+        .current_dir(dest)
+        .args(["checkout", "-q", "FETCH_HEAD"]));
+}
+
+/// Compile a grammar's `parser.c` (and optional `scanner.c`/`scanner.cc`) and tell
+/// Cargo to link the resulting static library.
+fn compile(name: &str, src: &Path) {
+    let mut build = cc::Build::new();
+    build.include(src).warnings(false).file(src.join("parser.c"));
+    if src.join("scanner.c").exists() {
+        build.file(src.join("scanner.c"));
+    }
+    if src.join("scanner.cc").exists() {
+        build.cpp(true).file(src.join("scanner.cc"));
+    }
+    build.compile(&format!("tree_sitter_{name}"));
+}
+
+fn run(cmd: &mut Command) {
+    let status = cmd.status().expect("spawn command");
+    assert!(status.success(), "command failed: {cmd:?}");
+}
+
+/// Minimal parser for the array-of-tables subset of TOML used by `languages.toml`.
+fn parse_grammars(manifest: &str) -> Vec<Grammar> {
+    let mut grammars: Vec<Grammar> = Vec::new();
+    let mut current: Option<GrammarBuilder> = None;
+
+    for line in manifest.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "[[grammar]]" {
+            if let Some(b) = current.take() {
+                grammars.push(b.build());
+            }
+            current = Some(GrammarBuilder::default());
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let b = current.as_mut().expect("key outside [[grammar]]");
+        let (key, value) = (key.trim(), value.trim());
+        match key {
+            "name" => b.name = Some(unquote(value)),
+            "repo" => b.repo = Some(unquote(value)),
+            "rev" => b.rev = Some(unquote(value)),
+            "path" => b.path = Some(unquote(value)),
+            "highlights" => b.highlights = Some(unquote(value)),
+            "injections" => b.injections = Some(unquote(value)),
+            "locals" => b.locals = Some(unquote(value)),
+            "aliases" => b.aliases = parse_array(value),
+            _ => {}
+        }
+    }
+    if let Some(b) = current.take() {
+        grammars.push(b.build());
+    }
+    grammars
+}
+
+#[derive(Default)]
+struct GrammarBuilder {
+    name: Option<String>,
+    aliases: Vec<String>,
+    repo: Option<String>,
+    rev: Option<String>,
+    path: Option<String>,
+    highlights: Option<String>,
+    injections: Option<String>,
+    locals: Option<String>,
+}
+
+impl GrammarBuilder {
+    fn build(self) -> Grammar {
+        Grammar {
+            name: self.name.expect("grammar without name"),
+            aliases: self.aliases,
+            repo: self.repo.expect("grammar without repo"),
+            rev: self.rev.expect("grammar without rev"),
+            path: self.path,
+            highlights: self.highlights,
+            injections: self.injections,
+            locals: self.locals,
+        }
+    }
+}
+
+fn unquote(s: &str) -> String {
+    s.trim().trim_matches('"').to_string()
+}
+
+fn parse_array(s: &str) -> Vec<String> {
+    s.trim()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| p.trim_matches('"').to_string())
+        .collect()
+}
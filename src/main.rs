@@ -1,110 +1,625 @@
 use regex::Regex;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, hash_map::DefaultHasher},
     env::{self},
     error::Error,
-    fs, process,
+    fs,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    process,
 };
-use tree_sitter::Language;
-use tree_sitter_highlight::{Highlight, HighlightConfiguration, Highlighter, HtmlRenderer};
-
-unsafe extern "C" {
-    fn tree_sitter_rust() -> Language;
-    fn tree_sitter_javascript() -> Language;
-    fn tree_sitter_html() -> Language;
-    fn tree_sitter_css() -> Language;
-    fn tree_sitter_python() -> Language;
-}
-
-fn language_map() -> HashMap<&'static str, (Language, &'static str, &'static str, &'static str)> {
-    let mut m = HashMap::new();
-    m.insert(
-        "rust",
-        (
-            unsafe { tree_sitter_rust() },
-            tree_sitter_rust::HIGHLIGHTS_QUERY,
-            "",
-            "",
-        ),
-    );
-    m.insert(
-        "javascript",
-        (
-            unsafe { tree_sitter_javascript() },
-            tree_sitter_javascript::HIGHLIGHT_QUERY,
-            "",
-            "",
-        ),
-    );
-    m.insert(
-        "html",
-        (
-            unsafe { tree_sitter_html() },
-            tree_sitter_html::HIGHLIGHTS_QUERY,
-            "",
-            "",
-        ),
-    );
-    m.insert(
-        "css",
-        (
-            unsafe { tree_sitter_css() },
-            tree_sitter_css::HIGHLIGHTS_QUERY,
-            "",
-            "",
-        ),
-    );
-    m.insert(
-        "python",
-        (
-            unsafe { tree_sitter_python() },
-            tree_sitter_python::HIGHLIGHTS_QUERY,
-            "",
-            "",
-        ),
-    );
-    m
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser as MarkdownParser, Tag, TagEnd, html};
+use sha2::{Digest, Sha512};
+use tree_sitter::{Language, Parser, Query, QueryCursor};
+use tree_sitter_highlight::{HighlightConfiguration, HighlightEvent, Highlighter};
+
+/// A single grammar in the build-time registry.
+///
+/// The registry itself is generated by `build.rs` from `languages.toml`: every
+/// grammar listed there is cloned, compiled, and emitted as one of these entries
+/// into `$OUT_DIR/grammars.rs`, which is `include!`d below. Adding a language is a
+/// `languages.toml` edit — the highlighter never changes.
+pub struct GrammarInfo {
+    /// Canonical language name, used as the config-map key and for injection lookup.
+    pub name: &'static str,
+    /// Extra tokens — file extensions and class-name aliases — that resolve here
+    /// besides `name` (e.g. `ts` for TypeScript); the `lang-` prefix is stripped by
+    /// [`resolve_grammar`] and need not be listed.
+    pub aliases: &'static [&'static str],
+    /// Parser entry point linked from the compiled grammar.
+    pub language: unsafe extern "C" fn() -> Language,
+    pub highlights: &'static str,
+    pub injections: &'static str,
+    pub locals: &'static str,
+}
+
+// Generated by build.rs: the `extern "C"` parser declarations and the `GRAMMARS`
+// table embedding each grammar's `highlights.scm`/`injections.scm`/`locals.scm`.
+include!(concat!(env!("OUT_DIR"), "/grammars.rs"));
+
+/// Resolve a class token (`rust`, `lang-ts`, `ts`, `typescript`, …) to its grammar,
+/// honouring the `lang-` prefix convention and the per-grammar alias list.
+fn resolve_grammar(token: &str) -> Option<&'static GrammarInfo> {
+    let token = token.strip_prefix("lang-").unwrap_or(token);
+    GRAMMARS
+        .iter()
+        .find(|g| g.name == token || g.aliases.contains(&token))
+}
+
+/// Find a grammar by its canonical name.
+fn grammar_by_name(name: &str) -> Option<&'static GrammarInfo> {
+    GRAMMARS.iter().find(|g| g.name == name)
+}
+
+/// A source token that participates in intra-block cross-referencing.
+enum Link {
+    /// A binding definition; carries the DOM id to stamp on it.
+    Definition(String),
+    /// A use of a binding; carries the id of its definition to link to.
+    Reference(String),
+}
+
+/// Maps a token's byte range to the [`Link`] role it plays, built from a grammar's
+/// `locals.scm`. References resolve to the nearest enclosing definition of the same
+/// name, so each rendered block gets rustdoc-style jump-to-definition links.
+struct LinkMap {
+    spans: HashMap<(usize, usize), Link>,
+}
+
+impl LinkMap {
+    fn empty() -> LinkMap {
+        LinkMap {
+            spans: HashMap::new(),
+        }
+    }
+
+    fn get(&self, start: usize, end: usize) -> Option<&Link> {
+        self.spans.get(&(start, end))
+    }
+}
+
+/// Turn identifier text into a DOM id: keep it verbatim when it is already a safe
+/// token (no whitespace, control, or punctuation beyond `_`/`-`), otherwise fall
+/// back to a stable hashed id.
+fn sanitize_id(name: &str) -> String {
+    let trimmed = name.trim();
+    let safe = !trimmed.is_empty()
+        && trimmed
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+    if safe {
+        trimmed.to_string()
+    } else {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        format!("id{:x}", hasher.finish())
+    }
+}
+
+/// Analyse a block with its grammar's locals query, resolving references to their
+/// definitions and assigning each referenced definition a collision-free id within
+/// the block. Definitions that no reference resolves to get no id.
+fn build_links(language_name: &str, source: &str) -> LinkMap {
+    let Some(grammar) = grammar_by_name(language_name) else {
+        return LinkMap::empty();
+    };
+    if grammar.locals.is_empty() {
+        return LinkMap::empty();
+    }
+
+    let language = unsafe { (grammar.language)() };
+    let mut parser = Parser::new();
+    if parser.set_language(&language).is_err() {
+        return LinkMap::empty();
+    }
+    let Some(tree) = parser.parse(source, None) else {
+        return LinkMap::empty();
+    };
+    let Ok(query) = Query::new(&language, grammar.locals) else {
+        return LinkMap::empty();
+    };
+
+    let mut scopes: Vec<(usize, usize)> = Vec::new();
+    let mut defs: Vec<((usize, usize), String)> = Vec::new();
+    let mut refs: Vec<((usize, usize), String)> = Vec::new();
+
+    let bytes = source.as_bytes();
+    let mut cursor = QueryCursor::new();
+    let mut matches = cursor.matches(&query, tree.root_node(), bytes);
+    while let Some(m) = matches.next() {
+        for cap in m.captures {
+            let range = cap.node.byte_range();
+            let span = (range.start, range.end);
+            let text = source[range].to_string();
+            match query.capture_names()[cap.index as usize] {
+                "local.scope" => scopes.push(span),
+                "local.definition" => defs.push((span, text)),
+                "local.reference" => refs.push((span, text)),
+                _ => {}
+            }
+        }
+    }
+
+    // Smallest scope containing `span`, used to rank candidate definitions.
+    let innermost_scope = |span: (usize, usize)| -> Option<(usize, usize)> {
+        scopes
+            .iter()
+            .filter(|s| s.0 <= span.0 && span.1 <= s.1)
+            .min_by_key(|s| s.1 - s.0)
+            .copied()
+    };
+
+    let mut spans: HashMap<(usize, usize), Link> = HashMap::new();
+    let mut ids: HashMap<(usize, usize), String> = HashMap::new();
+    let mut counter = 0usize;
+
+    for (span, name) in &refs {
+        // Candidate definitions share the name and enclose the reference; prefer
+        // the one in the innermost containing scope.
+        let best = defs
+            .iter()
+            .filter(|(_, dname)| dname == name)
+            .filter_map(|(dspan, _)| {
+                innermost_scope(*dspan).and_then(|s| {
+                    if s.0 <= span.0 && span.1 <= s.1 {
+                        Some((*dspan, s.1 - s.0))
+                    } else {
+                        None
+                    }
+                })
+            })
+            .min_by_key(|(_, size)| *size)
+            .map(|(dspan, _)| dspan);
+
+        if let Some(dspan) = best {
+            let id = ids.entry(dspan).or_insert_with(|| {
+                let name = defs
+                    .iter()
+                    .find(|(s, _)| *s == dspan)
+                    .map(|(_, n)| n.as_str())
+                    .unwrap_or("");
+                let id = format!("{}-{}", sanitize_id(name), counter);
+                counter += 1;
+                id
+            });
+            spans.insert(*span, Link::Reference(id.clone()));
+        }
+    }
+
+    for (dspan, id) in ids {
+        spans.insert(dspan, Link::Definition(id));
+    }
+
+    LinkMap { spans }
+}
+
+/// Prefix every `id`/`href="#…"` in a rendered block with its page ordinal so the
+/// jump-to-definition anchors stay unique across blocks — including identical
+/// blocks that the content cache serves from the same fragment.
+fn namespace_ids(fragment: &str, ordinal: usize) -> String {
+    let id_re = Regex::new(r#"id="([^"]*)""#).unwrap();
+    let href_re = Regex::new(r##"href="#([^"]*)""##).unwrap();
+    let step = id_re.replace_all(fragment, |c: &regex::Captures| {
+        format!("id=\"b{ordinal}-{}\"", &c[1])
+    });
+    href_re
+        .replace_all(&step, |c: &regex::Captures| {
+            format!("href=\"#b{ordinal}-{}\"", &c[1])
+        })
+        .to_string()
+}
+
+/// Styling for one capture name: a foreground color and font emphasis.
+struct Style {
+    color: Option<String>,
+    bold: bool,
+    italic: bool,
+}
+
+impl Style {
+    /// CSS declarations for this style, e.g. `color:#b48ead;font-weight:bold`.
+    fn css(&self) -> String {
+        let mut decls = Vec::new();
+        if let Some(color) = &self.color {
+            decls.push(format!("color:{color}"));
+        }
+        if self.bold {
+            decls.push("font-weight:bold".to_string());
+        }
+        if self.italic {
+            decls.push("font-style:italic".to_string());
+        }
+        decls.join(";")
+    }
+}
+
+/// A highlight theme: capture name → [`Style`].
+struct Theme {
+    styles: HashMap<String, Style>,
+}
+
+impl Theme {
+    /// Load a theme by `name`: a built-in (`base16-ocean`, `github`) or, failing
+    /// that, a path to a custom theme file.
+    fn load(name: &str) -> Result<Theme, Box<dyn Error>> {
+        let builtin = match name {
+            "base16-ocean" => Some(include_str!("../themes/base16-ocean.theme")),
+            "github" => Some(include_str!("../themes/github.theme")),
+            _ => None,
+        };
+        let text = match builtin {
+            Some(t) => t.to_string(),
+            None => fs::read_to_string(name)
+                .map_err(|e| format!("unknown theme `{name}` and not a readable file ({e})"))?,
+        };
+        Ok(Theme::parse(&text))
+    }
+
+    /// Parse the `capture = #color [bold] [italic]` theme format.
+    fn parse(text: &str) -> Theme {
+        let mut styles = HashMap::new();
+        for line in text.lines() {
+            let line = line.trim();
+            // `#` also opens colors, so only a leading `#` is a comment.
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((capture, rest)) = line.split_once('=') else {
+                continue;
+            };
+            let (capture, rest) = (capture.trim(), rest.trim());
+            if capture.is_empty() {
+                continue;
+            }
+            let mut style = Style {
+                color: None,
+                bold: false,
+                italic: false,
+            };
+            for token in rest.split_whitespace() {
+                match token {
+                    "bold" => style.bold = true,
+                    "italic" => style.italic = true,
+                    c if c.starts_with('#') => style.color = Some(c.to_string()),
+                    _ => {}
+                }
+            }
+            styles.insert(capture.to_string(), style);
+        }
+        Theme { styles }
+    }
+
+    /// Look up a capture's style, falling back to successively broader captures
+    /// (`function.macro` → `function`) the way tree-sitter themes expect.
+    fn style_for(&self, capture: &str) -> Option<&Style> {
+        let mut key = capture;
+        loop {
+            if let Some(style) = self.styles.get(key) {
+                return Some(style);
+            }
+            match key.rfind('.') {
+                Some(i) => key = &key[..i],
+                None => return None,
+            }
+        }
+    }
+
+    /// Inline CSS for the innermost styled capture in `stack`, if any.
+    fn inline_style(&self, stack: &[&str]) -> Option<String> {
+        stack
+            .iter()
+            .rev()
+            .find_map(|c| self.style_for(c))
+            .map(Style::css)
+    }
+
+    /// A `<style>` block defining a rule for every used capture that the theme
+    /// styles, targeting the `.sourceCode .<capture>` classes the renderer emits.
+    fn stylesheet(&self, used: &HashSet<String>) -> String {
+        let mut rules: Vec<String> = used
+            .iter()
+            .filter_map(|capture| {
+                self.style_for(capture)
+                    .map(|s| format!(".sourceCode .{} {{ {} }}", capture, s.css()))
+            })
+            .collect();
+        rules.sort();
+        format!("<style>\n{}\n</style>\n", rules.join("\n"))
+    }
+}
+
+/// How spans are decorated: semantic classes plus a generated stylesheet, or
+/// self-contained inline `style` attributes.
+struct RenderOptions<'a> {
+    theme: Option<&'a Theme>,
+    theme_name: Option<String>,
+    inline: bool,
+    cache_dir: Option<PathBuf>,
+}
+
+/// The grammar manifest, embedded so its digest can invalidate the cache when a
+/// grammar `rev` changes independently of the crate version.
+const LANGUAGES_TOML: &str = include_str!("../languages.toml");
+
+impl RenderOptions<'_> {
+    /// A tag mixed into every cache key so cached fragments are invalidated when
+    /// the crate, its pinned grammar revisions, or the render mode changes.
+    fn cache_tag(&self) -> String {
+        let mut hasher = Sha512::new();
+        hasher.update(LANGUAGES_TOML.as_bytes());
+        let grammars = format!("{:x}", hasher.finalize());
+        format!(
+            "v{};grammars={};inline={};theme={}",
+            env!("CARGO_PKG_VERSION"),
+            &grammars[..16],
+            self.inline,
+            self.theme_name.as_deref().unwrap_or("-"),
+        )
+    }
+}
+
+/// One cached block: its rendered fragment plus the capture names it used, so a
+/// cache hit can still contribute to the generated stylesheet.
+struct CacheEntry {
+    html: String,
+    used: Vec<String>,
+}
+
+/// Content-addressed cache of highlighted blocks, keyed by a SHA-512 digest of
+/// `(tag, language_name, source)`. Optionally persists each entry to `--cache-dir`
+/// as a file named by its hex digest, so re-runs over a static site skip blocks
+/// already rendered in a previous run.
+struct Cache {
+    dir: Option<PathBuf>,
+    mem: HashMap<String, CacheEntry>,
+}
+
+impl Cache {
+    fn new(dir: Option<PathBuf>) -> Cache {
+        if let Some(dir) = &dir
+            && let Err(e) = fs::create_dir_all(dir)
+        {
+            eprintln!("Couldn't create cache dir {} ({e})", dir.display());
+        }
+        Cache {
+            dir,
+            mem: HashMap::new(),
+        }
+    }
+
+    fn key(tag: &str, language_name: &str, source: &str) -> String {
+        let mut hasher = Sha512::new();
+        hasher.update(tag.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(language_name.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(source.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn get(&mut self, key: &str) -> Option<&CacheEntry> {
+        if !self.mem.contains_key(key)
+            && let Some(dir) = &self.dir
+            && let Ok(raw) = fs::read_to_string(dir.join(key))
+        {
+            self.mem.insert(key.to_string(), decode_entry(&raw));
+        }
+        self.mem.get(key)
+    }
+
+    fn put(&mut self, key: String, entry: CacheEntry) {
+        if let Some(dir) = &self.dir
+            && let Err(e) = fs::write(dir.join(&key), encode_entry(&entry))
+        {
+            eprintln!("Couldn't write cache entry ({e})");
+        }
+        self.mem.insert(key, entry);
+    }
+}
+
+/// Serialize a cache entry: the space-joined used captures on the first line,
+/// then the rendered fragment.
+fn encode_entry(entry: &CacheEntry) -> String {
+    format!("{}\n{}", entry.used.join(" "), entry.html)
+}
+
+fn decode_entry(raw: &str) -> CacheEntry {
+    let (header, html) = raw.split_once('\n').unwrap_or(("", raw));
+    CacheEntry {
+        html: html.to_string(),
+        used: header.split_whitespace().map(str::to_string).collect(),
+    }
+}
+
+/// Build and `configure()` every language's [`HighlightConfiguration`] once into a
+/// long-lived map keyed by language name. The configs must outlive the
+/// `highlight()` call so the injection callback can borrow sibling configs from the
+/// same map; a shared recognized-name list keeps [`Highlight`] indices consistent
+/// across languages, so the render callback can resolve any capture regardless of
+/// which (possibly injected) grammar produced it.
+fn build_configs() -> Result<(HashMap<String, HighlightConfiguration>, Vec<String>), Box<dyn Error>>
+{
+    let mut configs: HashMap<String, HighlightConfiguration> = HashMap::new();
+    for g in GRAMMARS {
+        let language = unsafe { (g.language)() };
+        let config =
+            HighlightConfiguration::new(language, g.name, g.highlights, g.injections, g.locals)?;
+        configs.insert(g.name.to_string(), config);
+    }
+
+    let mut names: Vec<String> = Vec::new();
+    for config in configs.values() {
+        for n in config.names() {
+            if !names.iter().any(|e| e == n) {
+                names.push(n.to_string());
+            }
+        }
+    }
+
+    let name_refs: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+    for config in configs.values_mut() {
+        config.configure(&name_refs);
+    }
+
+    Ok((configs, names))
 }
 
 /// Highlight source (raw code string, not HTML-escaped).
+///
+/// Injected languages (e.g. `<script>`/`<style>` inside HTML, or SQL/regex inside
+/// string literals) are resolved recursively by looking the injected language name
+/// up in `configs`.
 fn highlight_to_html(
-    language: Language,
+    configs: &HashMap<String, HighlightConfiguration>,
+    names: &[String],
     language_name: &str,
-    highlights_query: &str,
-    injections_query: &str,
-    locals_query: &str,
     source: &str,
+    opts: &RenderOptions,
+    used: &mut HashSet<String>,
 ) -> Result<String, Box<dyn Error>> {
-    let mut config = HighlightConfiguration::new(
-        language,
-        language_name,
-        highlights_query,
-        injections_query,
-        locals_query,
-    )?;
-
-    // copy names first to avoid borrow conflicts
-    let names_vec: Vec<String> = config.names().iter().map(|s| s.to_string()).collect();
-    let names_slice: Vec<&str> = names_vec.iter().map(|s| s.as_str()).collect();
-    config.configure(&names_slice);
+    let config = configs
+        .get(language_name)
+        .ok_or_else(|| format!("no highlight configuration for `{language_name}`"))?;
 
     let mut highlighter = Highlighter::new();
-    let iter = highlighter.highlight(&config, source.as_bytes(), None, |_| None)?;
+    let iter = highlighter.highlight(config, source.as_bytes(), None, |injected| {
+        configs.get(injected)
+    })?;
 
-    let mut renderer = HtmlRenderer::new();
+    let links = build_links(language_name, source);
+    render_events(iter, names, source.as_bytes(), opts, used, &links)
+}
 
-    let names_for_cb = names_vec; // move into closure
-    let attribute_callback = move |h: Highlight, out: &mut Vec<u8>| {
-        if let Some(name) = names_for_cb.get(h.0) {
-            let classes = name.replace('.', " ");
-            out.extend_from_slice(b"class=\"");
-            out.extend_from_slice(classes.as_bytes());
-            out.extend_from_slice(b"\"");
+/// Emit escaped token text, wrapping it in the cross-reference markup its [`Link`]
+/// role calls for: an `id` anchor for a definition, an `<a href>` for a reference.
+fn emit_text(out: &mut String, text: &str, link: Option<&Link>) {
+    match link {
+        Some(Link::Definition(id)) => {
+            out.push_str("<span id=\"");
+            out.push_str(id);
+            out.push_str("\">");
+            push_escaped(out, text);
+            out.push_str("</span>");
         }
-    };
+        Some(Link::Reference(id)) => {
+            out.push_str("<a href=\"#");
+            out.push_str(id);
+            out.push_str("\">");
+            push_escaped(out, text);
+            out.push_str("</a>");
+        }
+        None => push_escaped(out, text),
+    }
+}
 
-    renderer.render(iter, source.as_bytes(), &attribute_callback)?;
-    Ok(String::from_utf8(renderer.html)?)
+/// Append `s` to `out`, escaping the HTML-significant characters.
+fn push_escaped(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+/// Open a `<span>` for the active capture stack. In inline mode the span carries a
+/// `style` attribute resolved from the theme; otherwise it carries the semantic
+/// classes and every capture is recorded in `used` for later stylesheet generation.
+fn open_span(out: &mut String, stack: &[&str], opts: &RenderOptions, used: &mut HashSet<String>) {
+    if opts.inline {
+        match opts.theme.and_then(|t| t.inline_style(stack)) {
+            Some(decls) if !decls.is_empty() => {
+                out.push_str("<span style=\"");
+                out.push_str(&decls);
+                out.push_str("\">");
+            }
+            _ => out.push_str("<span>"),
+        }
+        return;
+    }
+
+    let classes = stack
+        .iter()
+        .map(|n| n.replace('.', " "))
+        .collect::<Vec<_>>()
+        .join(" ");
+    for capture in stack {
+        used.insert(capture.to_string());
+    }
+    out.push_str("<span class=\"");
+    out.push_str(&classes);
+    out.push_str("\">");
+}
+
+/// Hash the active capture-name stack so runs with identical attributes can share
+/// a single span.
+fn hash_stack(stack: &[&str]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    stack.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Render a [`HighlightEvent`] stream into HTML, coalescing adjacent tokens that
+/// carry the same highlight attributes into one `<span>`.
+///
+/// A `Vec<&str>` stack tracks the active capture names (pushed on `HighlightStart`,
+/// popped on `HighlightEnd`). For each `Source` slice we hash the stack and only
+/// close the open span and open a new one when the hash changes, so long runs of
+/// identically-highlighted tokens — and the per-line span churn the `HtmlRenderer`
+/// used to produce — collapse into a single tag. When the stack is empty the text
+/// is emitted unwrapped.
+fn render_events(
+    events: impl Iterator<Item = Result<HighlightEvent, tree_sitter_highlight::Error>>,
+    names: &[String],
+    source: &[u8],
+    opts: &RenderOptions,
+    used: &mut HashSet<String>,
+    links: &LinkMap,
+) -> Result<String, Box<dyn Error>> {
+    let mut out = String::new();
+    let mut stack: Vec<&str> = Vec::new();
+    let mut open: Option<u64> = None;
+
+    for event in events {
+        match event? {
+            HighlightEvent::HighlightStart(h) => {
+                if let Some(name) = names.get(h.0) {
+                    stack.push(name.as_str());
+                }
+            }
+            HighlightEvent::HighlightEnd => {
+                stack.pop();
+            }
+            HighlightEvent::Source { start, end } => {
+                let text = std::str::from_utf8(&source[start..end])?;
+                if stack.is_empty() {
+                    if open.take().is_some() {
+                        out.push_str("</span>");
+                    }
+                    emit_text(&mut out, text, links.get(start, end));
+                    continue;
+                }
+
+                let hash = hash_stack(&stack);
+                if open != Some(hash) {
+                    if open.is_some() {
+                        out.push_str("</span>");
+                    }
+                    open_span(&mut out, &stack, opts, used);
+                    open = Some(hash);
+                }
+                emit_text(&mut out, text, links.get(start, end));
+            }
+        }
+    }
+
+    if open.is_some() {
+        out.push_str("</span>");
+    }
+    Ok(out)
 }
 
 /// Basic HTML entity unescape for common entities and numeric entities.
@@ -164,6 +679,154 @@ fn html_unescape(s: &str) -> String {
     out
 }
 
+/// Normalize Windows (`\r\n`) and classic-Mac (lone `\r`) line endings to `\n` so
+/// line counting and gutters don't drift on cross-platform-authored blocks.
+fn normalize_newlines(s: &str) -> String {
+    s.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Opt-in line decorations requested via attributes on the `<pre>`/`<code>` element.
+struct LineOptions {
+    number: bool,
+    start_from: usize,
+    highlighted: HashSet<usize>,
+}
+
+/// Pull a single `name=value` attribute (quoted or bare) out of an attribute string.
+fn extract_attr(attrs: &str, name: &str) -> Option<String> {
+    let re = Regex::new(&format!(
+        r#"(?i){}\s*=\s*["']?([^"'\s>]+)["']?"#,
+        regex::escape(name)
+    ))
+    .unwrap();
+    re.captures(attrs).map(|c| c[1].to_string())
+}
+
+/// Expand a `3,5-7` style list into the set of line numbers it names.
+fn parse_line_ranges(spec: &str) -> HashSet<usize> {
+    let mut lines = HashSet::new();
+    for part in spec.split(',') {
+        let part = part.trim();
+        if let Some((a, b)) = part.split_once('-') {
+            if let (Ok(a), Ok(b)) = (a.trim().parse::<usize>(), b.trim().parse::<usize>()) {
+                for n in a..=b {
+                    lines.insert(n);
+                }
+            }
+        } else if let Ok(n) = part.parse::<usize>() {
+            lines.insert(n);
+        }
+    }
+    lines
+}
+
+/// Collect line-decoration options from the `pre`/`code` classes and attributes,
+/// returning `None` when the block opts into no decorations.
+fn parse_line_options(pre_attrs: &str, code_attrs: &str) -> Option<LineOptions> {
+    let classes = format!(
+        "{} {}",
+        extract_class_attr(pre_attrs).unwrap_or_default(),
+        extract_class_attr(code_attrs).unwrap_or_default(),
+    );
+    let number = classes.split_whitespace().any(|c| c == "numberLines");
+
+    let start_from = extract_attr(code_attrs, "startFrom")
+        .or_else(|| extract_attr(pre_attrs, "startFrom"))
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(1);
+
+    let highlighted = extract_attr(code_attrs, "highlight")
+        .or_else(|| extract_attr(pre_attrs, "highlight"))
+        .map(|v| parse_line_ranges(&v))
+        .unwrap_or_default();
+
+    if !number && highlighted.is_empty() {
+        None
+    } else {
+        Some(LineOptions {
+            number,
+            start_from,
+            highlighted,
+        })
+    }
+}
+
+/// Split rendered highlight HTML into per-line fragments, closing every open
+/// span/anchor at each newline and reopening it on the next line so the coalesced
+/// spans stay balanced within each decorated line.
+fn split_lines(input: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    let mut open: Vec<(String, String)> = Vec::new();
+    let mut i = 0;
+    while i < input.len() {
+        let c = input[i..].chars().next().unwrap();
+        match c {
+            '<' => {
+                let end = input[i..].find('>').map(|j| i + j + 1).unwrap_or(input.len());
+                let tag = &input[i..end];
+                current.push_str(tag);
+                if tag.starts_with("</") {
+                    open.pop();
+                } else if !tag.ends_with("/>") {
+                    let name: String = tag[1..].chars().take_while(|c| c.is_alphanumeric()).collect();
+                    open.push((tag.to_string(), format!("</{name}>")));
+                }
+                i = end;
+            }
+            '\n' => {
+                for (_, close) in open.iter().rev() {
+                    current.push_str(close);
+                }
+                lines.push(std::mem::take(&mut current));
+                for (open_tag, _) in open.iter() {
+                    current.push_str(open_tag);
+                }
+                i += 1;
+            }
+            _ => {
+                current.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    lines.push(current);
+    lines
+}
+
+/// Interleave line numbers and highlighted-line markers into rendered block HTML.
+fn decorate_lines(rendered: &str, opts: &LineOptions) -> String {
+    let mut lines = split_lines(rendered);
+    // Drop the trailing empty line a final newline leaves behind.
+    if lines.len() > 1 && lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+
+    let mut out = String::new();
+    for (idx, line) in lines.iter().enumerate() {
+        let num = opts.start_from + idx;
+        let gutter = if opts.number {
+            format!("<span class=\"line-number\" aria-hidden=\"true\">{num}</span>")
+        } else {
+            String::new()
+        };
+
+        if opts.highlighted.contains(&num) {
+            out.push_str(&format!(
+                "<span class=\"highlighted-line\">{gutter}{line}</span>"
+            ));
+        } else if opts.number {
+            out.push_str(&format!("<span class=\"source-line\">{gutter}{line}</span>"));
+        } else {
+            out.push_str(line);
+        }
+        if idx + 1 < lines.len() {
+            out.push('\n');
+        }
+    }
+    out
+}
+
 fn extract_class_attr(attrs: &str) -> Option<String> {
     let re_dq = Regex::new(r#"class\s*=\s*"([^"]+)""#).unwrap();
     if let Some(cap) = re_dq.captures(attrs) {
@@ -237,14 +900,24 @@ fn update_or_add_class(attrs: &str, add_classes: &str, add_lang: Option<&str>) -
 
 /// Process HTML, find <pre ...><code ...> blocks, detect language from class on pre or code,
 /// decode entities, highlight, add `sourceCode` to both pre and code, and safely insert highlighted HTML.
-fn highlight_html(input: &str) -> String {
+fn highlight_html(input: &str, opts: &RenderOptions) -> String {
     let re = Regex::new(
         r"(?s)<pre(?P<pre_attrs>[^>]*)>\s*<code(?P<code_attrs>[^>]*)>(?P<code>.*?)</code>\s*</pre>",
     )
     .unwrap();
-    let configs = language_map();
+    let (configs, names) = match build_configs() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Couldn't build highlight configurations ({e})");
+            return input.to_string();
+        }
+    };
 
-    re.replace_all(input, |caps: &regex::Captures| {
+    let mut used: HashSet<String> = HashSet::new();
+    let mut cache = Cache::new(opts.cache_dir.clone());
+    let mut block_ordinal = 0usize;
+
+    let body = re.replace_all(input, |caps: &regex::Captures| {
         let pre_attrs = caps.name("pre_attrs").map(|m| m.as_str()).unwrap_or("");
         let code_attrs = caps.name("code_attrs").map(|m| m.as_str()).unwrap_or("");
         let code_html_escaped = caps.name("code").map(|m| m.as_str()).unwrap_or("");
@@ -256,8 +929,8 @@ fn highlight_html(input: &str) -> String {
         let mut lang_opt: Option<String> = None;
         if let Some(cc) = code_class.clone() {
             for token in cc.split_whitespace() {
-                if configs.contains_key(token) {
-                    lang_opt = Some(token.to_string());
+                if let Some(g) = resolve_grammar(token) {
+                    lang_opt = Some(g.name.to_string());
                     break;
                 }
             }
@@ -266,27 +939,46 @@ fn highlight_html(input: &str) -> String {
             && let Some(pc) = pre_class.clone()
         {
             for token in pc.split_whitespace() {
-                if configs.contains_key(token) {
-                    lang_opt = Some(token.to_string());
+                if let Some(g) = resolve_grammar(token) {
+                    lang_opt = Some(g.name.to_string());
                     break;
                 }
             }
         }
 
         if let Some(lang) = lang_opt {
-            let (lang_obj, highlights, injections, locals) = configs.get(lang.as_str()).unwrap();
-
-            let decoded = html_unescape(code_html_escaped);
-
-            match highlight_to_html(
-                lang_obj.clone(),
-                &lang,
-                highlights,
-                injections,
-                locals,
-                &decoded,
-            ) {
-                Ok(rendered) => {
+            let decoded = normalize_newlines(&html_unescape(code_html_escaped));
+
+            let key = Cache::key(&opts.cache_tag(), &lang, &decoded);
+            let rendered = if let Some(entry) = cache.get(&key) {
+                for capture in &entry.used {
+                    used.insert(capture.clone());
+                }
+                Some(entry.html.clone())
+            } else {
+                let mut block_used: HashSet<String> = HashSet::new();
+                match highlight_to_html(&configs, &names, &lang, &decoded, opts, &mut block_used) {
+                    Ok(html) => {
+                        let entry = CacheEntry {
+                            html: html.clone(),
+                            used: block_used.iter().cloned().collect(),
+                        };
+                        cache.put(key, entry);
+                        used.extend(block_used);
+                        Some(html)
+                    }
+                    Err(_) => None,
+                }
+            };
+
+            match rendered {
+                Some(rendered) => {
+                    let rendered = namespace_ids(&rendered, block_ordinal);
+                    block_ordinal += 1;
+                    let rendered = match parse_line_options(pre_attrs, code_attrs) {
+                        Some(line_opts) => decorate_lines(&rendered, &line_opts),
+                        None => rendered,
+                    };
                     let new_pre_attrs = update_or_add_class(pre_attrs, "sourceCode", None);
                     // ensure code gets both sourceCode and the language class so downstream CSS / js can find it
                     let new_code_attrs = update_or_add_class(code_attrs, "sourceCode", Some(&lang));
@@ -295,39 +987,192 @@ fn highlight_html(input: &str) -> String {
                         new_pre_attrs, new_code_attrs, rendered
                     )
                 }
-                Err(_) => caps[0].to_string(),
+                None => caps[0].to_string(),
             }
         } else {
             caps[0].to_string()
         }
     })
-    .to_string()
+    .to_string();
+
+    prepend_stylesheet(body, opts, &used)
+}
+
+/// In stylesheet mode, prepend rules for every capture the document actually used
+/// so the output is self-contained; inline mode needs no stylesheet.
+fn prepend_stylesheet(body: String, opts: &RenderOptions, used: &HashSet<String>) -> String {
+    match opts.theme {
+        Some(theme) if !opts.inline && !used.is_empty() => {
+            format!("{}{}", theme.stylesheet(used), body)
+        }
+        _ => body,
+    }
+}
+
+/// Render a single fenced code block: highlight it when the info string names a
+/// known grammar, otherwise fall back to a plain escaped block.
+fn render_code_block(
+    configs: &HashMap<String, HighlightConfiguration>,
+    names: &[String],
+    lang: Option<&str>,
+    source: &str,
+    opts: &RenderOptions,
+    used: &mut HashSet<String>,
+    ordinal: usize,
+) -> String {
+    let grammar = lang
+        .and_then(|l| l.split_whitespace().next())
+        .and_then(resolve_grammar);
+
+    if let Some(g) = grammar
+        && let Ok(rendered) = highlight_to_html(configs, names, g.name, source, opts, used)
+    {
+        let rendered = namespace_ids(&rendered, ordinal);
+        return format!(
+            "<div class=\"sourceCode\"><pre class=\"sourceCode {name}\"><code class=\"sourceCode {name}\">{rendered}</code></pre></div>",
+            name = g.name,
+        );
+    }
+
+    let mut escaped = String::new();
+    push_escaped(&mut escaped, source);
+    format!("<pre><code>{escaped}</code></pre>")
+}
+
+/// Parse `input` as CommonMark and render it to HTML, highlighting fenced code
+/// blocks through the grammar registry. The fence body is fed to the highlighter
+/// raw — pulldown-cmark hands us un-escaped source — so entities aren't
+/// double-escaped the way a separate Markdown renderer would produce.
+fn highlight_markdown(input: &str, opts: &RenderOptions) -> String {
+    let (configs, names) = match build_configs() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Couldn't build highlight configurations ({e})");
+            let mut body = String::new();
+            html::push_html(&mut body, MarkdownParser::new_ext(input, Options::all()));
+            return body;
+        }
+    };
+
+    let mut used: HashSet<String> = HashSet::new();
+    let mut events: Vec<Event> = Vec::new();
+    let mut in_code = false;
+    let mut code_lang: Option<String> = None;
+    let mut code_buf = String::new();
+    let mut block_ordinal = 0usize;
+
+    for event in MarkdownParser::new_ext(input, Options::all()) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                in_code = true;
+                code_buf.clear();
+                code_lang = match kind {
+                    CodeBlockKind::Fenced(info) => Some(info.to_string()),
+                    CodeBlockKind::Indented => None,
+                };
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code = false;
+                let rendered = render_code_block(
+                    &configs,
+                    &names,
+                    code_lang.as_deref(),
+                    &code_buf,
+                    opts,
+                    &mut used,
+                    block_ordinal,
+                );
+                block_ordinal += 1;
+                events.push(Event::Html(rendered.into()));
+            }
+            Event::Text(text) if in_code => code_buf.push_str(&text),
+            other => events.push(other),
+        }
+    }
+
+    let mut body = String::new();
+    html::push_html(&mut body, events.into_iter());
+    prepend_stylesheet(body, opts, &used)
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {program} [--theme <name>] [--inline] [--markdown] [--cache-dir <dir>] input"
+    );
+    process::exit(1);
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    let program = args.first().map(String::as_str).unwrap_or("htmlhl");
+
+    let mut theme_name: Option<String> = None;
+    let mut inline = false;
+    let mut markdown = false;
+    let mut cache_dir: Option<PathBuf> = None;
+    let mut file: Option<String> = None;
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} input.html", args[0]);
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--theme" => {
+                i += 1;
+                match args.get(i) {
+                    Some(name) => theme_name = Some(name.clone()),
+                    None => usage(program),
+                }
+            }
+            "--cache-dir" => {
+                i += 1;
+                match args.get(i) {
+                    Some(dir) => cache_dir = Some(PathBuf::from(dir)),
+                    None => usage(program),
+                }
+            }
+            "--inline" => inline = true,
+            "--markdown" => markdown = true,
+            arg if file.is_none() => file = Some(arg.to_string()),
+            _ => usage(program),
+        }
+        i += 1;
+    }
+
+    let Some(file) = file else { usage(program) };
+
+    let theme = match &theme_name {
+        Some(name) => match Theme::load(name) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                eprintln!("Couldn't load theme ({e})");
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    if inline && theme.is_none() {
+        eprintln!("--inline requires --theme <name>");
         process::exit(1);
     }
 
-    let file = &args[1];
-    let html = match fs::read_to_string(file) {
+    let html = match fs::read_to_string(&file) {
         Ok(s) => s,
         Err(e) => {
             eprintln!("Couldn't read {file} ({e})");
             process::exit(1);
         }
     };
-    // let html = r#"
-    //     <p>Rust:</p>
-    //     <pre><code class="rust">println!("Hello");</code></pre>
-    //
-    //     <p>JS:</p>
-    //     <pre><code class="javascript">let x = 42;</code></pre>
-    // "#;
-
-    let highlighted = highlight_html(&html);
+
+    let opts = RenderOptions {
+        theme: theme.as_ref(),
+        theme_name,
+        inline,
+        cache_dir,
+    };
+    let highlighted = if markdown {
+        highlight_markdown(&html, &opts)
+    } else {
+        highlight_html(&html, &opts)
+    };
     print!("{}", highlighted);
 }